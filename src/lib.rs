@@ -6,31 +6,78 @@
 //! A ImmutableString can have multiple owners.
 //! Once every owner drops a ImmutableString, it is lazily removed from the WeakHashSet and dealocated.
 //!
-//! The globally shared WeakHashSet is protected by a RwLock which allows multiple concurrent readers but guarantees that any writer has exclusive access.
+//! Each shard of the globally shared table (see below) is a WeakHashSet protected by its own
+//! `parking_lot::RwLock`, which allows multiple concurrent readers but guarantees that any writer
+//! has exclusive access. Unlike `std::sync::RwLock`,
+//! it is task-fair: new readers are blocked while a writer is waiting, so writers cannot be starved.
+//! It also never poisons, so lock acquisition cannot fail.
 //! When instantiating an ImmutableString, the constructor first acquires a reader to check whether the value is already present in the map.
 //! If not, it forgoes the reader lock and attempt to acquire the exclusive writer lock.
 //! Once it has exclusive writer access, it checks again if the string is not present in the map.
 //! Then, it allocates the string and store a weak copy in the hashmap.
 //!
-//! The globally shared WeakHashSet may present a performance bottleneck and in the future should be replaced by a distribuited hashmap.
+//! To avoid serializing unrelated insertions on a single writer lock, the table is sharded into
+//! `SHARD_COUNT` independent `RwLock<WeakHashSet<Weak<str>>>` shards, `SHARD_COUNT` being a power
+//! of two derived from the available parallelism. Each string is routed to a shard by masking the
+//! low bits of its hash, so distinct strings landing on different shards can be interned
+//! concurrently.
 
 use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use std::{
     borrow::Borrow,
+    collections::hash_map::DefaultHasher,
     fmt,
+    hash::{Hash, Hasher},
     iter::{FromIterator, IntoIterator},
     ops::Deref,
-    sync::{Arc, RwLock, Weak},
+    sync::{Arc, Weak},
 };
 use weak_table::WeakHashSet;
 
 lazy_static! {
-    static ref STRING_TABLE: RwLock<WeakHashSet<Weak<str>>> = RwLock::new(WeakHashSet::new());
+    static ref SHARD_COUNT: usize = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .next_power_of_two();
+    static ref STRING_TABLE: Vec<RwLock<WeakHashSet<Weak<str>>>> =
+        (0..*SHARD_COUNT).map(|_| RwLock::new(WeakHashSet::new())).collect();
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+/// Returns the shard of `STRING_TABLE` responsible for `s`, selected by masking the low bits of
+/// `s`'s hash against `SHARD_COUNT - 1`.
+#[inline]
+fn shard_for(s: &str) -> &'static RwLock<WeakHashSet<Weak<str>>> {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    let shard = hasher.finish() as usize & (*SHARD_COUNT - 1);
+    &STRING_TABLE[shard]
+}
+
+/// Because every distinct string value is interned into exactly one `Arc<str>`, two
+/// `ImmutableString`s hold equal data iff they share the same allocation. `PartialEq`/`Eq` and
+/// `Hash` exploit this invariant: equality is `Arc::ptr_eq` and hashing is over the pointer
+/// address, both O(1) regardless of string length. `PartialOrd`/`Ord` still compare lexically, so
+/// `Hash` is consistent with `Eq` but not with the ordering.
+#[derive(PartialOrd, Ord, Clone)]
 pub struct ImmutableString(Arc<str>);
 
+impl PartialEq for ImmutableString {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ImmutableString {}
+
+impl Hash for ImmutableString {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.0) as *const () as usize).hash(state);
+    }
+}
+
 impl ImmutableString {
     /// Returns the number of ImmutableStrings referencing the same data.
     ///
@@ -51,6 +98,40 @@ impl ImmutableString {
     pub fn use_count(&self) -> usize {
         Arc::strong_count(&self.0)
     }
+
+    /// Returns the number of live, distinct strings currently interned across all shards.
+    ///
+    /// ```
+    /// use immutable_string::*;
+    ///
+    /// let before = ImmutableString::interned_count();
+    /// let _a = ImmutableString::from("a fresh string that was not interned before");
+    /// assert_eq!(ImmutableString::interned_count(), before + 1);
+    /// ```
+    pub fn interned_count() -> usize {
+        STRING_TABLE
+            .iter()
+            .map(|shard| shard.read().iter().count())
+            .sum()
+    }
+
+    /// Returns the total length, in bytes, of every live, distinct string currently interned
+    /// across all shards. Useful for measuring how much deduplication a parse actually saved.
+    pub fn interned_bytes() -> usize {
+        STRING_TABLE
+            .iter()
+            .map(|shard| shard.read().iter().map(|s| s.len()).sum::<usize>())
+            .sum()
+    }
+
+    /// Forces every shard to evict `Weak` entries whose strong count has reached zero, instead of
+    /// waiting for the `WeakHashSet` to reap them lazily. Useful for reclaiming hashmap slots right
+    /// after a parse phase ends.
+    pub fn compact() {
+        for shard in STRING_TABLE.iter() {
+            shard.write().remove_expired();
+        }
+    }
 }
 
 impl fmt::Display for ImmutableString {
@@ -72,13 +153,15 @@ where
     T: ?Sized + Deref<Target = str> + Into<Arc<str>>,
 {
     fn from(s: T) -> Self {
+        let shard = shard_for(&s);
+
         // Attempt to aquire string without locking the hashmap first
-        let str_map = STRING_TABLE.read().expect("Corrupted STRING_TABLE");
+        let str_map = shard.read();
         if let Some(val) = str_map.get(&s) {
             ImmutableString(val)
         } else {
             drop(str_map); //Drop read lock to aquire write lock
-            let mut str_map = STRING_TABLE.write().expect("Corrupted STRING_TABLE");
+            let mut str_map = shard.write();
 
             // Double check if string was not inserted after asking for write lock
             if let Some(val) = str_map.get(&s) {
@@ -133,6 +216,25 @@ impl FromIterator<char> for ImmutableString {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for ImmutableString {
+    #[inline]
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ImmutableString {
+    // Deserializes into an owned String first, then routes it through the same interning path as
+    // `From`, so that repeated strings across a document collapse onto a single shared allocation
+    // instead of each producing its own Arc<str>.
+    #[inline]
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(ImmutableString::from)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -166,4 +268,35 @@ mod tests {
         let a = ImmutableString::from("a");
         assert_eq!(format!("a: {}", a), "a: a");
     }
+
+    // STRING_TABLE is process-global and shared with every other test in this binary, so this
+    // test asserts strict increases and decreases around its own string, not exact counts against
+    // a snapshot that other concurrently-running tests may also be mutating. Strings that other
+    // tests intern or drop in between can still move these totals.
+    #[test]
+    fn compact_reclaims_dropped_strings() {
+        let unique = "a string unique enough not to collide with other tests";
+        let before = ImmutableString::interned_count();
+        let bytes_before = ImmutableString::interned_bytes();
+
+        let a = ImmutableString::from(unique);
+        let with_a = ImmutableString::interned_count();
+        let bytes_with_a = ImmutableString::interned_bytes();
+        assert!(with_a > before);
+        assert!(bytes_with_a > bytes_before);
+
+        drop(a);
+        ImmutableString::compact();
+        assert!(ImmutableString::interned_count() < with_a);
+        assert!(ImmutableString::interned_bytes() < bytes_with_a);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize_reinterns_repeated_strings() {
+        let json = r#"["a json string unique enough not to collide", "a json string unique enough not to collide"]"#;
+        let strings: Vec<ImmutableString> = serde_json::from_str(json).unwrap();
+        assert_eq!(strings[0], strings[1]);
+        assert_eq!(strings[0].use_count(), 2);
+    }
 }